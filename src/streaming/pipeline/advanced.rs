@@ -5,17 +5,24 @@
 //! servers have more of a peer relationship, it's useful to work directly with
 //! these implementation details.
 
-use futures::sync::mpsc;
+use futures::sync::{mpsc, oneshot};
 use futures::{Future, Poll, Async, Stream, Sink, AsyncSink, StartSend};
+use futures::task::{self, Task};
+use std::time::Duration;
 use std::{fmt, io};
+use tokio_core::reactor::{Handle, Timeout};
 use crate::streaming::{Message, Body};
 use super::{Frame, Transport};
 use crate::buffer_one::BufferOne;
 
-// TODO:
-//
-// - Wait for service readiness
-// - Handle request body stream cancellation
+/// The default cap on how many requests `Pipeline` will read out of the
+/// transport and dispatch before any responses have been written back.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+
+/// Number of frames `Pipeline` will process within a single `poll` call
+/// before yielding back to the executor, so one busy connection can't starve
+/// the other tasks on the reactor.
+const YIELD_EVERY: usize = 16;
 
 /// Provides protocol pipelining functionality in a generic way over clients
 /// and servers. Used internally by `pipeline::Client` and `pipeline::Server`.
@@ -37,6 +44,57 @@ pub struct Pipeline<T> where T: Dispatch {
 
     // True when the transport is fully flushed
     is_flushed: bool,
+
+    // Number of messages dispatched to the service that haven't yet had
+    // their response written back to the transport.
+    in_flight: usize,
+
+    // Stop reading new requests off the transport once `in_flight` reaches
+    // this many.
+    max_in_flight: usize,
+
+    // Number of frames processed so far within the current `poll` call.
+    spin_count: usize,
+
+    // Cached handle to notify once a yield is requested, so that repeated
+    // yields on a hot connection don't need to re-register with the
+    // executor each time.
+    task: Option<Task>,
+
+    // Set once the service has signaled that the in-flight response is the
+    // last one; the transport is closed as soon as it finishes flushing.
+    closing: bool,
+
+    // Set once `process_out_body_chunk` detects that the receiver half of
+    // the current request body has dropped interest, until the transport
+    // actually yields the `Frame::Body { chunk: None }` terminator for it.
+    // While set, the leftover body bytes of the just-canceled request are
+    // still arriving and must be drained regardless of the new-message
+    // gates in `read_out_frames` — those gates must not stall on a
+    // service readiness, in-flight count, or shutdown state that may only
+    // resolve once this body is fully drained.
+    draining_canceled_body: bool,
+
+    // Idle keep-alive duration and the reactor handle used to arm timers,
+    // if keep-alive is configured.
+    keep_alive: Option<(Duration, Handle)>,
+
+    // The currently-armed idle timer, if any. Disarmed whenever the
+    // connection has work in flight.
+    keep_alive_timeout: Option<Timeout>,
+
+    // Fires when a caller asks this pipeline to shut down gracefully,
+    // letting any in-flight request finish before closing.
+    shutdown_signal: Option<oneshot::Receiver<()>>,
+
+    // Set once the pipeline has decided to shut the connection down, either
+    // because the keep-alive timer fired while idle or a graceful shutdown
+    // was requested. While set, no new requests are read off the transport.
+    shutting_down: bool,
+
+    // True once `DispatchSink::close` has reported the sink fully closed;
+    // mirrors how `is_flushed` tracks `poll_complete`.
+    is_closed: bool,
 }
 
 /// Message used to communicate through the multiplex dispatch
@@ -78,9 +136,37 @@ pub trait Dispatch {
     /// Poll the next completed message
     fn poll(&mut self) -> Poll<Option<PipelineMessage<Self::In, Self::Stream, Self::Error>>, io::Error>;
 
+    /// Poll whether the service behind this dispatch is ready to accept
+    /// another message.
+    ///
+    /// Until this returns `Async::Ready`, the pipeline will not read another
+    /// message out of the transport, providing backpressure to the peer.
+    fn poll_ready(&mut self) -> Poll<(), Self::Error>;
+
     /// RPC currently in flight
     /// TODO: Get rid of
     fn has_in_flight(&self) -> bool;
+
+    /// Returns true if the connection should be closed after the message
+    /// most recently returned from `poll` has been fully written to the
+    /// transport.
+    ///
+    /// This lets protocols like HTTP/1's `Connection: close` or IMAP's
+    /// `BYE` signal that the current response is the last one, so the
+    /// pipeline can shut the transport down cleanly once it's flushed
+    /// rather than waiting on the peer to close its half of the socket.
+    fn should_close(&self) -> bool;
+
+    /// Called when the receiver half of the current request body stream
+    /// has dropped interest before the body was fully read off the
+    /// transport.
+    ///
+    /// The pipeline itself keeps consuming and discarding `Frame::Body`
+    /// chunks for the canceled request until it sees the terminating
+    /// `None`, so framing stays in sync; this hook lets the transport or
+    /// codec know to drain or skip the remaining body rather than treat it
+    /// as belonging to the next request.
+    fn cancel_out_body(&mut self);
 }
 
 struct DispatchSink<T> {
@@ -106,23 +192,169 @@ impl<T> Pipeline<T> where T: Dispatch {
             out_body: None,
             in_body: None,
             is_flushed: true,
+            in_flight: 0,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            spin_count: 0,
+            task: None,
+            closing: false,
+            draining_canceled_body: false,
+            keep_alive: None,
+            keep_alive_timeout: None,
+            shutdown_signal: None,
+            shutting_down: false,
+            is_closed: false,
         }
     }
 
+    /// Set the maximum number of in-flight requests this pipeline will allow
+    /// before it stops reading new requests off the transport.
+    ///
+    /// Defaults to `DEFAULT_MAX_IN_FLIGHT`.
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Pipeline<T> {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// Close the connection after it has been idle (no in-flight request)
+    /// for `duration`.
+    pub fn keep_alive(mut self, handle: &Handle, duration: Duration) -> Pipeline<T> {
+        self.keep_alive = Some((duration, handle.clone()));
+        self
+    }
+
+    /// Request a graceful shutdown once `signal` fires: any request
+    /// currently in flight is allowed to complete, but no further requests
+    /// are read off the transport and the connection closes once the
+    /// response drains.
+    pub fn graceful_shutdown(mut self, signal: oneshot::Receiver<()>) -> Pipeline<T> {
+        self.shutdown_signal = Some(signal);
+        self
+    }
+
     /// Returns true if the pipeline server dispatch has nothing left to do
     fn is_done(&self) -> bool {
         (!self.transport_open || !self.request_sender_open) && self.is_flushed && !self.has_in_flight()
     }
 
+    // Record that a frame was processed within the current `poll` call.
+    // Returns true once `YIELD_EVERY` frames have been processed, signaling
+    // that the caller should stop and yield back to the executor.
+    fn record_spin(&mut self) -> bool {
+        self.spin_count += 1;
+        self.spin_count >= YIELD_EVERY
+    }
+
+    // Check whether a graceful shutdown has been requested, transitioning
+    // to the shutting-down state if so.
+    fn poll_shutdown_signal(&mut self) {
+        if self.shutting_down {
+            return;
+        }
+
+        let fired = match self.shutdown_signal {
+            Some(ref mut signal) => match signal.poll() {
+                Ok(Async::NotReady) => false,
+                Ok(Async::Ready(())) | Err(_) => true,
+            },
+            None => false,
+        };
+
+        if fired {
+            self.shutdown_signal = None;
+            self.shutting_down = true;
+        }
+    }
+
+    // Drive the idle keep-alive timer: arm it while the connection has
+    // nothing in flight, disarm it as soon as it does, and transition to
+    // the shutting-down state once it fires.
+    fn poll_keep_alive(&mut self) -> io::Result<()> {
+        if self.shutting_down {
+            return Ok(());
+        }
+
+        if self.in_flight > 0 || self.in_body.is_some() {
+            self.keep_alive_timeout = None;
+            return Ok(());
+        }
+
+        let (duration, handle) = match self.keep_alive {
+            Some((duration, ref handle)) => (duration, handle),
+            None => return Ok(()),
+        };
+
+        if self.keep_alive_timeout.is_none() {
+            self.keep_alive_timeout = Some(Timeout::new(duration, handle)?);
+        }
+
+        if let Async::Ready(()) = self.keep_alive_timeout.as_mut().unwrap().poll()? {
+            self.shutting_down = true;
+        }
+
+        Ok(())
+    }
+
+    // Notify the current task so it is polled again immediately, giving the
+    // executor a chance to service other connections in the meantime.
+    fn yield_now(&mut self) {
+        if self.task.is_none() {
+            self.task = Some(task::current());
+        }
+
+        self.task.as_ref().unwrap().notify();
+    }
+
     fn read_out_frames(&mut self) -> io::Result<()> {
         while self.transport_open {
+            // While `out_body` is set, or while we're still draining the
+            // leftover body frames of a request whose body was just
+            // canceled, the next frame off the transport is necessarily a
+            // body continuation, not a fresh `Frame::Message` header.
+            // Gates that only make sense for accepting a *new* request
+            // must not apply in that case, or the very request that
+            // tripped the gate would have its own body frames blocked
+            // forever.
+            let awaiting_new_message =
+                self.out_body.is_none() && !self.draining_canceled_body;
+
+            // Once shutting down (idle timeout or a graceful shutdown
+            // request) or the service has signaled it wants the connection
+            // closed after its current response, stop accepting new
+            // requests but let the request already in flight keep reading
+            // its remaining body frames so it can finish.
+            if awaiting_new_message && (self.shutting_down || self.closing) {
+                break;
+            }
+
+            // Don't pull more requests off the transport than we're willing
+            // to hold in flight at once; once `write_in_frames` drains
+            // responses below the limit, `poll` has the task polled again so
+            // reading resumes.
+            if awaiting_new_message && self.in_flight >= self.max_in_flight {
+                break;
+            }
+
             // Return true if the pipeliner can process new outbound frames
             if !self.check_out_body_stream() {
                 break;
             }
 
+            // Don't read a new message out of the transport until the
+            // service is ready to accept it; this provides backpressure
+            // instead of buffering unboundedly in the service. A request
+            // already dispatched must keep reading its body regardless,
+            // since the service's readiness may depend on that request
+            // finishing in the first place.
+            if awaiting_new_message && !self.check_dispatch_ready()? {
+                break;
+            }
+
             if let Async::Ready(frame) = self.dispatch.get_mut().inner.transport().poll()? {
                 self.process_out_frame(frame)?;
+
+                if self.record_spin() {
+                    break;
+                }
             } else {
                 break;
             }
@@ -131,6 +363,17 @@ impl<T> Pipeline<T> where T: Dispatch {
         Ok(())
     }
 
+    // Returns true if the service is ready to accept another dispatched
+    // message. Calling `poll_ready` also ensures the task is notified once
+    // the service becomes ready, so the pipeline will be polled again.
+    fn check_dispatch_ready(&mut self) -> io::Result<bool> {
+        match self.dispatch.get_mut().inner.poll_ready() {
+            Ok(Async::Ready(())) => Ok(true),
+            Ok(Async::NotReady) => Ok(false),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "service not ready")),
+        }
+    }
+
     fn check_out_body_stream(&mut self) -> bool {
         let body = match self.out_body {
             Some(ref mut body) => body,
@@ -148,6 +391,8 @@ impl<T> Pipeline<T> where T: Dispatch {
         // frame, no matter what it is.
         match frame {
             Some(Frame::Message { message, body }) => {
+                self.in_flight += 1;
+
                 if body {
                     trace!("read out message with body");
 
@@ -183,6 +428,7 @@ impl<T> Pipeline<T> where T: Dispatch {
                         // Drop the sender.
                         // TODO: Ensure a sender exists
                         let _ = self.out_body.take();
+                        self.draining_canceled_body = false;
                     }
                 }
             }
@@ -229,6 +475,8 @@ impl<T> Pipeline<T> where T: Dispatch {
         }
         if reset {
             self.out_body = None;
+            self.draining_canceled_body = true;
+            self.dispatch.get_mut().inner.cancel_out_body();
         }
         Ok(())
     }
@@ -247,11 +495,29 @@ impl<T> Pipeline<T> where T: Dispatch {
             match self.dispatch.get_mut().inner.poll()? {
                 Async::Ready(Some(Ok(message))) => {
                     trace!("   --> got message");
+                    self.in_flight = self.in_flight.saturating_sub(1);
+                    // Latch, don't overwrite: once any response has asked
+                    // to close the connection, a later in-flight response
+                    // that doesn't must not un-close it.
+                    self.closing = self.closing || self.dispatch.get_ref().inner.should_close();
                     self.write_in_message(Ok(message))?;
+
+                    if self.record_spin() {
+                        break;
+                    }
                 }
                 Async::Ready(Some(Err(error))) => {
                     trace!("   --> got error");
+                    self.in_flight = self.in_flight.saturating_sub(1);
+                    // Latch, don't overwrite: once any response has asked
+                    // to close the connection, a later in-flight response
+                    // that doesn't must not un-close it.
+                    self.closing = self.closing || self.dispatch.get_ref().inner.should_close();
                     self.write_in_message(Err(error))?;
+
+                    if self.record_spin() {
+                        break;
+                    }
                 }
                 Async::Ready(None) => {
                     trace!("   --> got None");
@@ -318,10 +584,19 @@ impl<T> Pipeline<T> where T: Dispatch {
                     Ok(Async::Ready(Some(chunk))) => {
                         assert_send(&mut self.dispatch,
                                          Frame::Body { chunk: Some(chunk) })?;
+
+                        // A long-lived response body must not be allowed to
+                        // spin this loop forever within one `poll` call, or
+                        // it starves the reactor exactly like the frame
+                        // read loops this is meant to protect.
+                        if self.record_spin() {
+                            return Ok(false);
+                        }
                     }
                     Ok(Async::Ready(None)) => {
                         assert_send(&mut self.dispatch,
                                          Frame::Body { chunk: None })?;
+                        self.record_spin();
                         break;
                     }
                     Err(_) => {
@@ -368,18 +643,77 @@ impl<T> Future for Pipeline<T> where T: Dispatch {
     fn poll(&mut self) -> Poll<(), io::Error> {
         trace!("Pipeline::tick");
 
+        // Reset the spin counter for this call to `poll`.
+        self.spin_count = 0;
+
         // Always tick the transport first
         self.dispatch.get_mut().inner.transport().tick();
 
+        // Check for a graceful shutdown request and drive the idle
+        // keep-alive timer before deciding whether to read more requests.
+        self.poll_shutdown_signal();
+        self.poll_keep_alive()?;
+        let in_flight_before = self.in_flight;
+
         // First read off data from the socket
         self.read_out_frames()?;
 
+        // A request read this call ends the idle period; drop the armed
+        // timer so it is re-armed from scratch once the connection is idle.
+        if self.in_flight > in_flight_before {
+            self.keep_alive_timeout = None;
+        }
+
+        if self.spin_count >= YIELD_EVERY {
+            self.yield_now();
+            return Ok(Async::NotReady);
+        }
+
+        // `read_out_frames` stops at the in-flight limit without polling the
+        // transport, so nothing else wakes the task once responses drain.
+        let at_limit = self.in_flight >= self.max_in_flight;
+
         // Handle completed responses
         self.write_in_frames()?;
 
+        if self.spin_count >= YIELD_EVERY {
+            self.yield_now();
+            return Ok(Async::NotReady);
+        }
+
+        if at_limit && self.in_flight < self.max_in_flight {
+            self.yield_now();
+        }
+
         // Try flushing buffered writes
         self.flush()?;
 
+        // If the service asked to close the connection after the response
+        // it just produced, and that response (including any body) has
+        // been fully flushed, stop waiting on the transport's read half
+        // and let the pipeline drain and close on its own.
+        if self.closing && self.in_body.is_none() && self.is_flushed {
+            self.request_sender_open = false;
+        }
+
+        // Arm the idle timer if this call left the connection idle.
+        self.poll_keep_alive()?;
+
+        // Once shutting down with nothing left in flight and the response
+        // fully flushed, close the sink and let the pipeline drain. Closing
+        // can itself be asynchronous (e.g. a TLS close_notify), so track it
+        // the same way `flush` tracks `is_flushed` rather than assuming one
+        // call is enough.
+        if self.shutting_down && self.in_body.is_none() && self.is_flushed && !self.has_in_flight() {
+            if !self.is_closed {
+                self.is_closed = self.dispatch.close()?.is_ready();
+            }
+
+            if self.is_closed {
+                self.request_sender_open = false;
+            }
+        }
+
         // Clean shutdown of the pipeline server can happen when
         //
         // 1. The server is done running, this is signaled by Transport::poll()
@@ -418,6 +752,13 @@ impl<T> fmt::Debug for Pipeline<T>
             .field("out_body", &"Sender { ... }")
             .field("in_body", &self.in_body)
             .field("is_flushed", &self.is_flushed)
+            .field("in_flight", &self.in_flight)
+            .field("max_in_flight", &self.max_in_flight)
+            .field("spin_count", &self.spin_count)
+            .field("closing", &self.closing)
+            .field("draining_canceled_body", &self.draining_canceled_body)
+            .field("shutting_down", &self.shutting_down)
+            .field("is_closed", &self.is_closed)
             .finish()
     }
 }